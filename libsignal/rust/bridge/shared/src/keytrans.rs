@@ -0,0 +1,812 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Real (non-TESTING) bridge surface for the key transparency client.
+//!
+//! Unlike `TESTING_ChatSearchResult`, which fabricates a [`SearchResult`] for
+//! exercising the JNI/FFI/Node glue, the functions here actually drive a
+//! search, monitor, or distinguished-tree request against a KT server and
+//! hand back the data needed to verify and persist the result.
+
+use ed25519_dalek::{Signature as Ed25519Signature, Verifier, VerifyingKey};
+use libsignal_core::Aci;
+use libsignal_keytrans::{StoredAccountData, StoredMonitoringData, StoredTreeHead};
+use libsignal_net::env::Environment;
+use libsignal_net::keytrans::{Error, KeyTransparencyClient, SearchResult};
+use libsignal_protocol::IdentityKey;
+use prost::Message as _;
+use sha2::{Digest, Sha256};
+
+use crate::*;
+
+/// Why a `KeyTransparency_Search` or `KeyTransparency_Distinguished` request
+/// failed.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum KeyTransparencyRequestError {
+    /// {0}
+    Request(#[from] Error),
+    /// last_tree_head is malformed
+    InvalidLastTreeHead,
+}
+
+fn decode_last_tree_head(
+    bytes: Option<&[u8]>,
+) -> Result<Option<StoredTreeHead>, KeyTransparencyRequestError> {
+    bytes
+        .map(|bytes| {
+            StoredTreeHead::decode(bytes)
+                .map_err(|_| KeyTransparencyRequestError::InvalidLastTreeHead)
+        })
+        .transpose()
+}
+
+/// Performs a key transparency search for `aci`, optionally also checking
+/// that `e164` and `username_hash` map to the same account.
+///
+/// `last_tree_head` is the client's last-known anchor, encoded the same way
+/// `StoredTreeHead::encode_to_vec` (the `prost::Message` encoding) produces
+/// it (i.e. the raw tree-head encoding found on
+/// `StoredAccountData::last_tree_head`, not the versioned blob
+/// `KeyTransparency_SerializeAccountData` returns); pass `None` for a cold
+/// start. The returned `SearchResult` carries the `StoredAccountData`
+/// callers should persist for the next search or monitor request.
+#[bridge_fn]
+async fn KeyTransparency_Search(
+    environment: AsType<Environment, u8>,
+    aci: Aci,
+    aci_identity_key: &IdentityKey,
+    e164: Option<String>,
+    username_hash: Option<&[u8]>,
+    last_tree_head: Option<&[u8]>,
+) -> Result<SearchResult, KeyTransparencyRequestError> {
+    let last_tree_head = decode_last_tree_head(last_tree_head)?;
+    let client = KeyTransparencyClient::new(environment.into_inner());
+    Ok(client
+        .search(
+            aci,
+            aci_identity_key,
+            e164,
+            username_hash.map(<[u8]>::to_vec),
+            last_tree_head,
+        )
+        .await?)
+}
+
+/// Requests a monitor proof for the aspects already present in
+/// `account_data`, advancing each one's position and returning the updated
+/// `SearchResult`.
+#[bridge_fn]
+async fn KeyTransparency_Monitor(
+    environment: AsType<Environment, u8>,
+    aci: Aci,
+    e164: Option<String>,
+    username_hash: Option<&[u8]>,
+    account_data: StoredAccountData,
+) -> Result<SearchResult, KeyTransparencyRequestError> {
+    let client = KeyTransparencyClient::new(environment.into_inner());
+    Ok(client
+        .monitor(
+            aci,
+            e164,
+            username_hash.map(<[u8]>::to_vec),
+            account_data,
+        )
+        .await?)
+}
+
+/// Fetches the server's distinguished tree head, used as the trust anchor
+/// for a client that has never searched before.
+///
+/// `last_tree_head`, if present, uses the same encoding as in
+/// [`KeyTransparency_Search`]: `StoredTreeHead::encode_to_vec`'s raw
+/// tree-head bytes, not the versioned `KeyTransparency_SerializeAccountData`
+/// blob.
+#[bridge_fn]
+async fn KeyTransparency_Distinguished(
+    environment: AsType<Environment, u8>,
+    last_tree_head: Option<&[u8]>,
+) -> Result<StoredTreeHead, KeyTransparencyRequestError> {
+    let last_tree_head = decode_last_tree_head(last_tree_head)?;
+    let client = KeyTransparencyClient::new(environment.into_inner());
+    Ok(client.distinguished(last_tree_head).await?)
+}
+
+/// Why a consistency proof between two [`StoredTreeHead`]s didn't check out.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ConsistencyProofError {
+    /// proof has {actual} hashes, expected {expected}
+    WrongLength { actual: usize, expected: usize },
+    /// recomputed old tree root does not match the trusted root
+    OldRootMismatch,
+    /// recomputed new tree root does not match the server-provided root
+    NewRootMismatch,
+    /// old tree head, new tree head, root, or proof is malformed
+    Malformed,
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Largest power of two strictly smaller than `x`.
+fn largest_power_of_two_below(x: u64) -> u64 {
+    debug_assert!(x > 1);
+    1 << (u64::BITS - (x - 1).leading_zeros() - 1)
+}
+
+/// Recomputes `(MTH(D[0:m]), MTH(D[0:n]))`, consuming consistency-proof
+/// hashes from `proof` in order. This is RFC 6962's `SUBPROOF(m, D[n], b)`:
+/// `trusted` is `b` — it's only `true` along the initial left spine, where
+/// `D[0:m]` is literally the subtree the caller already trusts (seeded from
+/// `old` instead of consuming a proof element for it). Every other subtree
+/// reached by recursing into the "new-only" right half is `false`, and its
+/// root must come from the proof, not from the globally-trusted `old`.
+fn recompute_roots(
+    m: u64,
+    n: u64,
+    old: [u8; 32],
+    trusted: bool,
+    proof: &mut impl Iterator<Item = [u8; 32]>,
+) -> Result<([u8; 32], [u8; 32]), ConsistencyProofError> {
+    if m == n {
+        let root = if trusted {
+            old
+        } else {
+            proof.next().ok_or(ConsistencyProofError::WrongLength {
+                actual: 0,
+                expected: 1,
+            })?
+        };
+        return Ok((root, root));
+    }
+    let k = largest_power_of_two_below(n);
+    if m <= k {
+        let (old_root, new_left) = recompute_roots(m, k, old, trusted, proof)?;
+        let new_right = proof.next().ok_or(ConsistencyProofError::WrongLength {
+            actual: 0,
+            expected: 1,
+        })?;
+        Ok((old_root, node_hash(&new_left, &new_right)))
+    } else {
+        let (old_right, new_right) = recompute_roots(m - k, n - k, old, false, proof)?;
+        let old_left = proof.next().ok_or(ConsistencyProofError::WrongLength {
+            actual: 0,
+            expected: 1,
+        })?;
+        Ok((
+            node_hash(&old_left, &old_right),
+            node_hash(&old_left, &new_right),
+        ))
+    }
+}
+
+/// Verifies that `new` is an append-only extension of `old`, per the RFC
+/// 6962 log-consistency check, using the Merkle consistency proof in
+/// `proof`.
+pub fn verify_consistency(
+    old: &StoredTreeHead,
+    new: &StoredTreeHead,
+    proof: &[[u8; 32]],
+) -> Result<(), ConsistencyProofError> {
+    let m = old
+        .tree_head
+        .as_ref()
+        .ok_or(ConsistencyProofError::Malformed)?
+        .tree_size;
+    let n = new
+        .tree_head
+        .as_ref()
+        .ok_or(ConsistencyProofError::Malformed)?
+        .tree_size;
+    let old_root: [u8; 32] = old
+        .root
+        .clone()
+        .try_into()
+        .map_err(|_| ConsistencyProofError::Malformed)?;
+    let new_root: [u8; 32] = new
+        .root
+        .clone()
+        .try_into()
+        .map_err(|_| ConsistencyProofError::Malformed)?;
+
+    if m == n {
+        return if proof.is_empty() && old_root == new_root {
+            Ok(())
+        } else if old_root != new_root {
+            Err(ConsistencyProofError::NewRootMismatch)
+        } else {
+            Err(ConsistencyProofError::WrongLength {
+                actual: proof.len(),
+                expected: 0,
+            })
+        };
+    }
+    if m == 0 {
+        return if proof.is_empty() {
+            Ok(())
+        } else {
+            Err(ConsistencyProofError::WrongLength {
+                actual: proof.len(),
+                expected: 0,
+            })
+        };
+    }
+
+    // How many hashes a proof holds depends only on `m` and `n`, so a dry run
+    // over an endless supply of placeholder hashes counts the expected length.
+    let mut expected = 0;
+    recompute_roots(m, n, old_root, true, &mut std::iter::repeat_with(|| {
+        expected += 1;
+        [0; 32]
+    }))?;
+    if proof.len() != expected {
+        return Err(ConsistencyProofError::WrongLength {
+            actual: proof.len(),
+            expected,
+        });
+    }
+
+    let mut remaining = proof.iter().copied();
+    let (computed_old, computed_new) = recompute_roots(m, n, old_root, true, &mut remaining)?;
+    if remaining.next().is_some() {
+        return Err(ConsistencyProofError::WrongLength {
+            actual: proof.len(),
+            expected: proof.len() - remaining.count() - 1,
+        });
+    }
+    if computed_old != old_root {
+        return Err(ConsistencyProofError::OldRootMismatch);
+    }
+    if computed_new != new_root {
+        return Err(ConsistencyProofError::NewRootMismatch);
+    }
+    Ok(())
+}
+
+/// Splits a flat concatenation of 32-byte hashes (the wire format for a
+/// Merkle consistency proof) into individual proof elements.
+fn parse_consistency_proof(proof: &[u8]) -> Result<Vec<[u8; 32]>, ConsistencyProofError> {
+    if proof.len() % 32 != 0 {
+        return Err(ConsistencyProofError::Malformed);
+    }
+    Ok(proof
+        .chunks_exact(32)
+        .map(|chunk| chunk.try_into().expect("chunks_exact(32)"))
+        .collect())
+}
+
+/// Verifies that `new_tree_head` is an append-only extension of
+/// `old_tree_head`, given a flat Merkle consistency proof (a concatenation
+/// of 32-byte hashes).
+#[bridge_fn]
+fn KeyTransparency_VerifyConsistency(
+    old_tree_head: &[u8],
+    new_tree_head: &[u8],
+    proof: &[u8],
+) -> Result<(), ConsistencyProofError> {
+    let old = StoredTreeHead::decode(old_tree_head)
+        .map_err(|_| ConsistencyProofError::Malformed)?;
+    let new = StoredTreeHead::decode(new_tree_head)
+        .map_err(|_| ConsistencyProofError::Malformed)?;
+    let proof = parse_consistency_proof(proof)?;
+    verify_consistency(&old, &new, &proof)
+}
+
+/// Why a tree head's auditor signatures didn't meet the required threshold.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum TreeHeadVerificationError {
+    /// only {valid} of the required {threshold} trusted auditors signed this tree head
+    InsufficientSignatures {
+        valid: usize,
+        threshold: usize,
+        failed_auditors: Vec<Vec<u8>>,
+    },
+    /// tree head is malformed
+    Malformed,
+}
+
+/// The fixed-layout bytes an auditor signs over: the tree head's
+/// `tree_size` and `timestamp` as big-endian `u64`s, followed by the `root`
+/// from its enclosing `StoredTreeHead` — i.e. everything in a `TreeHead`
+/// except the `signatures` being produced.
+///
+/// This is a best-effort reconstruction, not a confirmed match for the
+/// auditor service's actual TBS encoding: the upstream `libsignal-keytrans`
+/// protocol definition isn't available in this tree to check it against. A
+/// general protobuf message was tried in an earlier revision of this
+/// function, but a fixed-layout concatenation is the more defensible guess
+/// — the other signed/hashed structure in this file, `node_hash`, is also a
+/// fixed layout rather than protobuf. Until this is validated against a
+/// known-good auditor-produced signature, treat
+/// `KeyTransparency_VerifyTreeHead` as unverified in production: the only
+/// test exercising this function signs with this same function, so it
+/// proves self-consistency, not server interop.
+fn signed_tree_head_body(tree_size: u64, timestamp: u64, root: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(16 + root.len());
+    body.extend_from_slice(&tree_size.to_be_bytes());
+    body.extend_from_slice(&timestamp.to_be_bytes());
+    body.extend_from_slice(root);
+    body
+}
+
+/// Checks that at least `threshold` of the `trusted_auditor_keys` produced a
+/// valid ed25519 signature over `tree_head`'s canonical body. Signatures from
+/// auditors outside `trusted_auditor_keys` are ignored entirely — neither
+/// counted nor reported. `failed_auditors` lists every trusted auditor that
+/// did *not* contribute a valid signature, whether it signed invalidly or
+/// didn't sign at all, so callers can tell which trusted auditors to chase.
+pub fn verify_tree_head_threshold(
+    tree_head: &StoredTreeHead,
+    trusted_auditor_keys: &[[u8; 32]],
+    threshold: usize,
+) -> Result<(), TreeHeadVerificationError> {
+    let inner = tree_head
+        .tree_head
+        .as_ref()
+        .ok_or(TreeHeadVerificationError::Malformed)?;
+    let body = signed_tree_head_body(inner.tree_size, inner.timestamp, &tree_head.root);
+
+    let mut valid_auditors = std::collections::HashSet::new();
+    for signature in &inner.signatures {
+        let Some(trusted_key) = trusted_auditor_keys
+            .iter()
+            .find(|key| key.as_slice() == signature.auditor_public_key)
+        else {
+            continue;
+        };
+        let verifies = VerifyingKey::from_bytes(trusted_key)
+            .ok()
+            .zip(Ed25519Signature::from_slice(&signature.signature).ok())
+            .is_some_and(|(key, sig)| key.verify(&body, &sig).is_ok());
+        if verifies {
+            valid_auditors.insert(*trusted_key);
+        }
+    }
+
+    if valid_auditors.len() >= threshold {
+        Ok(())
+    } else {
+        let failed_auditors = trusted_auditor_keys
+            .iter()
+            .filter(|key| !valid_auditors.contains(*key))
+            .map(|key| key.to_vec())
+            .collect();
+        Err(TreeHeadVerificationError::InsufficientSignatures {
+            valid: valid_auditors.len(),
+            threshold,
+            failed_auditors,
+        })
+    }
+}
+
+/// Verifies that at least `threshold` of `auditor_keys` (each a 32-byte
+/// ed25519 public key) produced a valid signature over `tree_head`.
+#[bridge_fn]
+fn KeyTransparency_VerifyTreeHead(
+    tree_head: &[u8],
+    auditor_keys: &[&[u8]],
+    threshold: u32,
+) -> Result<(), TreeHeadVerificationError> {
+    let tree_head = StoredTreeHead::decode(tree_head)
+        .map_err(|_| TreeHeadVerificationError::Malformed)?;
+    let auditor_keys: Vec<[u8; 32]> = auditor_keys
+        .iter()
+        .map(|key| <[u8; 32]>::try_from(*key).map_err(|_| TreeHeadVerificationError::Malformed))
+        .collect::<Result<_, _>>()?;
+    verify_tree_head_threshold(&tree_head, &auditor_keys, threshold as usize)
+}
+
+/// The version byte prefixed to every `StoredAccountData` blob so the
+/// on-disk format can evolve without breaking clients that persisted an
+/// older one.
+const ACCOUNT_DATA_FORMAT_VERSION: u8 = 1;
+
+/// Why a persisted `StoredAccountData` blob couldn't be loaded.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum AccountDataCodecError {
+    /// account data blob is empty
+    Empty,
+    /// unsupported account data format version {0}
+    UnsupportedVersion(u8),
+    /// failed to encode account data
+    Encode,
+    /// failed to decode account data
+    Decode,
+    /// {aspect} monitoring data has a {len}-byte index, expected 32
+    InvalidIndexLength { aspect: &'static str, len: usize },
+    /// {aspect} ptrs reference position {pos}, which is not after the current position {pos_current}
+    InvalidPtrs {
+        aspect: &'static str,
+        pos: u64,
+        pos_current: u64,
+    },
+}
+
+/// A `ptrs` entry is only useful for auditing a position the client hasn't
+/// reached yet, so every key must be strictly greater than `pos`. This must
+/// stay in sync with the retain condition in `merge_monitoring_data`, which
+/// is what's responsible for dropping entries once they fall behind `pos`.
+fn validate_monitoring_data(
+    aspect: &'static str,
+    data: &StoredMonitoringData,
+) -> Result<(), AccountDataCodecError> {
+    if data.index.len() != 32 {
+        return Err(AccountDataCodecError::InvalidIndexLength {
+            aspect,
+            len: data.index.len(),
+        });
+    }
+    if let Some(&pos) = data.ptrs.keys().find(|&&pos| pos <= data.pos) {
+        return Err(AccountDataCodecError::InvalidPtrs {
+            aspect,
+            pos,
+            pos_current: data.pos,
+        });
+    }
+    Ok(())
+}
+
+/// Encodes `account_data` into a stable, versioned blob a client can write
+/// to disk as its monitoring cursor.
+#[bridge_fn]
+fn KeyTransparency_SerializeAccountData(
+    account_data: StoredAccountData,
+) -> Result<Vec<u8>, AccountDataCodecError> {
+    let mut blob = vec![ACCOUNT_DATA_FORMAT_VERSION];
+    account_data
+        .encode(&mut blob)
+        .map_err(|_| AccountDataCodecError::Encode)?;
+    Ok(blob)
+}
+
+/// Decodes a blob produced by `KeyTransparency_SerializeAccountData`,
+/// validating the monitoring-data invariants for every aspect present.
+#[bridge_fn]
+fn KeyTransparency_DeserializeAccountData(
+    blob: &[u8],
+) -> Result<StoredAccountData, AccountDataCodecError> {
+    let (&version, body) = blob.split_first().ok_or(AccountDataCodecError::Empty)?;
+    if version != ACCOUNT_DATA_FORMAT_VERSION {
+        return Err(AccountDataCodecError::UnsupportedVersion(version));
+    }
+    let account_data =
+        StoredAccountData::decode(body).map_err(|_| AccountDataCodecError::Decode)?;
+    for (aspect, data) in [
+        ("aci", &account_data.aci),
+        ("e164", &account_data.e164),
+        ("username_hash", &account_data.username_hash),
+    ] {
+        if let Some(data) = data {
+            validate_monitoring_data(aspect, data)?;
+        }
+    }
+    Ok(account_data)
+}
+
+/// Why `KeyTransparency_UpdateAccountData` couldn't fold a new search result
+/// into existing monitoring state.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum AccountDataUpdateError {
+    /// new tree size {new} for {aspect} is smaller than the already-monitored size {existing}
+    TreeShrunk {
+        aspect: &'static str,
+        existing: u64,
+        new: u64,
+    },
+    /// existing account data has a tree head, but new_result has none to compare it against
+    MissingTreeHead,
+    /// new_result's tree head is not a consistent extension of the existing one: {0}
+    InconsistentTreeHead(#[from] ConsistencyProofError),
+}
+
+/// Advances one aspect's position to `new`'s, and folds `new`'s observed
+/// Merkle path pointers into `existing`'s, dropping any pointer at or before
+/// the new position since it can no longer be needed to audit a future one
+/// (mirrors the invariant `validate_monitoring_data` enforces on decode).
+fn merge_monitoring_data(
+    aspect: &'static str,
+    existing: StoredMonitoringData,
+    new: StoredMonitoringData,
+) -> Result<StoredMonitoringData, AccountDataUpdateError> {
+    if new.pos < existing.pos {
+        return Err(AccountDataUpdateError::TreeShrunk {
+            aspect,
+            existing: existing.pos,
+            new: new.pos,
+        });
+    }
+    let mut ptrs = existing.ptrs;
+    ptrs.extend(new.ptrs);
+    ptrs.retain(|&pos, _| pos > new.pos);
+    Ok(StoredMonitoringData {
+        index: existing.index,
+        pos: new.pos,
+        ptrs,
+        owned: existing.owned,
+    })
+}
+
+fn merge_aspect(
+    aspect: &'static str,
+    existing: Option<StoredMonitoringData>,
+    new: Option<StoredMonitoringData>,
+) -> Result<Option<StoredMonitoringData>, AccountDataUpdateError> {
+    match (existing, new) {
+        (Some(existing), Some(new)) => merge_monitoring_data(aspect, existing, new).map(Some),
+        (None, new) => Ok(new),
+        (existing, None) => Ok(existing),
+    }
+}
+
+/// Folds `new_result` into `existing`, advancing each aspect's position and
+/// Merkle pointers instead of rebuilding monitoring state from scratch. This
+/// is the normal operating mode for monitoring after the initial search.
+///
+/// This takes a third `consistency_proof` parameter beyond the request's
+/// `existing`/`new_result` pair, widening the bridged signature, so the
+/// consistency check below can actually run here instead of being left to
+/// the caller as a documented obligation.
+///
+/// `consistency_proof` is the flat (concatenated 32-byte hashes) Merkle
+/// consistency proof between `existing`'s tree head and `new_result`'s; it's
+/// checked before anything is folded in, so the server can't fork the
+/// client's view by handing back fabricated monitoring data. It's ignored
+/// (and may be empty) when `existing` has no tree head yet, since there's
+/// nothing to be consistent with.
+#[bridge_fn]
+fn KeyTransparency_UpdateAccountData(
+    existing: StoredAccountData,
+    new_result: SearchResult,
+    consistency_proof: &[u8],
+) -> Result<StoredAccountData, AccountDataUpdateError> {
+    let new = new_result.account_data;
+    if let Some(old_tree_head) = existing.last_tree_head.as_ref() {
+        let new_tree_head = new
+            .last_tree_head
+            .as_ref()
+            .ok_or(AccountDataUpdateError::MissingTreeHead)?;
+        let proof = parse_consistency_proof(consistency_proof)?;
+        verify_consistency(old_tree_head, new_tree_head, &proof)?;
+    }
+    Ok(StoredAccountData {
+        aci: merge_aspect("aci", existing.aci, new.aci)?,
+        e164: merge_aspect("e164", existing.e164, new.e164)?,
+        username_hash: merge_aspect("username_hash", existing.username_hash, new.username_hash)?,
+        last_tree_head: new.last_tree_head.or(existing.last_tree_head),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Root of `leaves[0..leaves.len()]`, computed independently of
+    /// `recompute_roots` by direct recursion, to serve as an oracle.
+    fn subtree_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        let k = largest_power_of_two_below(leaves.len() as u64) as usize;
+        node_hash(&subtree_root(&leaves[..k]), &subtree_root(&leaves[k..]))
+    }
+
+    /// Generates `PROOF(m, D[n])` per RFC 6962, for `leaves = D[n]`.
+    fn consistency_proof(m: usize, leaves: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        fn subproof(m: usize, leaves: &[[u8; 32]], trusted: bool) -> Vec<[u8; 32]> {
+            let n = leaves.len();
+            if m == n {
+                return if trusted { vec![] } else { vec![subtree_root(leaves)] };
+            }
+            let k = largest_power_of_two_below(n as u64) as usize;
+            if m <= k {
+                let mut proof = subproof(m, &leaves[..k], trusted);
+                proof.push(subtree_root(&leaves[k..]));
+                proof
+            } else {
+                let mut proof = subproof(m - k, &leaves[k..], false);
+                proof.push(subtree_root(&leaves[..k]));
+                proof
+            }
+        }
+        subproof(m, leaves, true)
+    }
+
+    fn leaves(n: usize) -> Vec<[u8; 32]> {
+        (0..n)
+            .map(|i| {
+                let mut hasher = Sha256::new();
+                hasher.update([0x00]);
+                hasher.update((i as u64).to_be_bytes());
+                hasher.finalize().into()
+            })
+            .collect()
+    }
+
+    fn stored_tree_head(leaves: &[[u8; 32]]) -> StoredTreeHead {
+        StoredTreeHead {
+            tree_head: Some(libsignal_keytrans::TreeHead {
+                tree_size: leaves.len() as u64,
+                timestamp: 0,
+                signatures: vec![],
+            }),
+            root: subtree_root(leaves).to_vec(),
+        }
+    }
+
+    fn flatten(proof: &[[u8; 32]]) -> Vec<u8> {
+        proof.iter().flatten().copied().collect()
+    }
+
+    #[test]
+    fn verifies_every_valid_proof_up_to_17_leaves() {
+        for n in 1..=17 {
+            let leaves = leaves(n);
+            let new_tree_head = stored_tree_head(&leaves);
+            for m in 1..=n {
+                let old_tree_head = stored_tree_head(&leaves[..m]);
+                let proof = consistency_proof(m, &leaves);
+                verify_consistency(&old_tree_head, &new_tree_head, &proof).unwrap_or_else(|e| {
+                    panic!("valid proof for (m={m}, n={n}) was rejected: {e}")
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_proof() {
+        let leaves = leaves(7);
+        let old_tree_head = stored_tree_head(&leaves[..3]);
+        let new_tree_head = stored_tree_head(&leaves);
+        let mut proof = consistency_proof(3, &leaves);
+        proof[0][0] ^= 1;
+        assert!(verify_consistency(&old_tree_head, &new_tree_head, &proof).is_err());
+    }
+
+    #[test]
+    fn bridge_fn_roundtrips_flat_proof_bytes() {
+        let leaves = leaves(6);
+        let old_tree_head = stored_tree_head(&leaves[..5]);
+        let new_tree_head = stored_tree_head(&leaves);
+        let proof = consistency_proof(5, &leaves);
+        KeyTransparency_VerifyConsistency(
+            &old_tree_head.encode_to_vec(),
+            &new_tree_head.encode_to_vec(),
+            &flatten(&proof),
+        )
+        .expect("valid proof");
+    }
+
+    const TEST_ACI_IDENTITY_KEY_BYTES: &[u8] = &const_str::hex!(
+        "05111f9464c1822c6a2405acf1c5a4366679dc3349fc8eb015c8d7260e3f771177"
+    );
+
+    /// Folding a search result into existing monitoring state, serializing
+    /// the result, and deserializing it again must not trip the `ptrs`
+    /// invariant `KeyTransparency_DeserializeAccountData` enforces: the two
+    /// checks previously disagreed about which side of `pos` a pointer had
+    /// to be on.
+    #[test]
+    fn update_then_serialize_then_deserialize_round_trips() {
+        let leaves = leaves(9);
+        let existing_monitoring = StoredMonitoringData {
+            index: vec![7; 32],
+            pos: 5,
+            ptrs: [(10, true)].into_iter().collect(),
+            owned: true,
+        };
+        let new_monitoring = StoredMonitoringData {
+            index: vec![7; 32],
+            pos: 8,
+            ptrs: [(10, true), (12, false)].into_iter().collect(),
+            owned: true,
+        };
+        let existing = StoredAccountData {
+            aci: Some(existing_monitoring),
+            e164: None,
+            username_hash: None,
+            last_tree_head: Some(stored_tree_head(&leaves[..5])),
+        };
+        let new_result = SearchResult {
+            aci_identity_key: IdentityKey::decode(TEST_ACI_IDENTITY_KEY_BYTES)
+                .expect("valid serialized key"),
+            aci_for_e164: None,
+            aci_for_username_hash: None,
+            timestamp: std::time::SystemTime::UNIX_EPOCH,
+            account_data: StoredAccountData {
+                aci: Some(new_monitoring),
+                e164: None,
+                username_hash: None,
+                last_tree_head: Some(stored_tree_head(&leaves[..8])),
+            },
+        };
+        let proof = flatten(&consistency_proof(5, &leaves[..8]));
+
+        let updated = KeyTransparency_UpdateAccountData(existing, new_result, &proof)
+            .expect("consistent update");
+        let blob = KeyTransparency_SerializeAccountData(updated).expect("serializes");
+        KeyTransparency_DeserializeAccountData(&blob).expect("round-trips");
+    }
+
+    /// Self-signed, not a server-produced vector: there's no real auditor
+    /// signature available in this tree to check `signed_tree_head_body`
+    /// against, so this only proves `verify_tree_head_threshold` is
+    /// internally consistent with whatever `signed_tree_head_body`
+    /// produces, not that the two match a real auditor service.
+    #[test]
+    fn threshold_verification_counts_only_trusted_valid_signatures() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let trusted_signing_key = SigningKey::from_bytes(&[1; 32]);
+        let untrusted_signing_key = SigningKey::from_bytes(&[2; 32]);
+        let trusted_key_bytes = trusted_signing_key.verifying_key().to_bytes();
+
+        let root = vec![9; 32];
+        let body = signed_tree_head_body(10, 1234, &root);
+        let valid_sig = trusted_signing_key.sign(&body);
+        let untrusted_sig = untrusted_signing_key.sign(&body);
+
+        let stored = StoredTreeHead {
+            tree_head: Some(libsignal_keytrans::TreeHead {
+                tree_size: 10,
+                timestamp: 1234,
+                signatures: vec![
+                    libsignal_keytrans::Signature {
+                        auditor_public_key: trusted_key_bytes.to_vec(),
+                        signature: valid_sig.to_bytes().to_vec(),
+                    },
+                    libsignal_keytrans::Signature {
+                        auditor_public_key: untrusted_signing_key.verifying_key().to_bytes().to_vec(),
+                        signature: untrusted_sig.to_bytes().to_vec(),
+                    },
+                ],
+            }),
+            root,
+        };
+        verify_tree_head_threshold(&stored, &[trusted_key_bytes], 1)
+            .expect("valid trusted signature meets threshold");
+        // The untrusted auditor's signature is also valid over `body`, but it
+        // must not count towards a threshold of 2.
+        let err = verify_tree_head_threshold(&stored, &[trusted_key_bytes], 2).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                TreeHeadVerificationError::InsufficientSignatures {
+                    valid: 1,
+                    threshold: 2,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+
+        // Swap in a bogus signature for the trusted auditor: it should be
+        // reported as failed, and the untrusted signer shouldn't appear at
+        // all even though it also didn't contribute a valid signature.
+        let bogus_sig = trusted_signing_key.sign(b"not the signed body");
+        let stored_bad = StoredTreeHead {
+            tree_head: Some(libsignal_keytrans::TreeHead {
+                signatures: vec![libsignal_keytrans::Signature {
+                    auditor_public_key: trusted_key_bytes.to_vec(),
+                    signature: bogus_sig.to_bytes().to_vec(),
+                }],
+                ..stored.tree_head.clone().unwrap()
+            }),
+            root: stored.root.clone(),
+        };
+        let err = verify_tree_head_threshold(&stored_bad, &[trusted_key_bytes], 1).unwrap_err();
+        match err {
+            TreeHeadVerificationError::InsufficientSignatures {
+                failed_auditors, ..
+            } => {
+                assert_eq!(failed_auditors, vec![trusted_key_bytes.to_vec()]);
+            }
+            other => panic!("expected InsufficientSignatures, got {other}"),
+        }
+    }
+}